@@ -0,0 +1,75 @@
+//! Thread local last-error handling shared by all FFI entry points.
+
+use anyhow::Error;
+use libc::{c_char, c_int};
+use std::{cell::RefCell, ffi::CString, slice};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// Update the `LAST_ERROR` with the provided error.
+pub fn update_last_error(err: Error) {
+    LAST_ERROR.with(|prev| {
+        *prev.borrow_mut() = CString::new(format!("{:#}", err)).ok();
+    });
+}
+
+/// Clear the `LAST_ERROR`.
+pub fn remove_last_error() {
+    LAST_ERROR.with(|prev| *prev.borrow_mut() = None);
+}
+
+/// Update the `LAST_ERROR` if the provided result is an error, otherwise
+/// clear it.
+pub fn update_last_err_if_required<T>(result: anyhow::Result<T>) {
+    match result {
+        Err(e) => update_last_error(e),
+        Ok(_) => remove_last_error(),
+    }
+}
+
+#[no_mangle]
+/// Return the length of the last error message, including the trailing
+/// null byte. Returns `0` if there is no last error.
+pub extern "C" fn last_error_length() -> c_int {
+    LAST_ERROR.with(|prev| match &*prev.borrow() {
+        Some(err) => err.as_bytes().len() as c_int + 1,
+        None => 0,
+    })
+}
+
+#[no_mangle]
+/// Write the last error message into the provided buffer, returning the
+/// number of bytes written or `-1` on failure.
+pub unsafe extern "C" fn last_error_message(buffer: *mut c_char, length: c_int) -> c_int {
+    if buffer.is_null() {
+        return -1;
+    }
+    let last_error = match LAST_ERROR.with(|prev| prev.borrow().clone()) {
+        Some(err) => err,
+        None => return 0,
+    };
+    let bytes = last_error.as_bytes_with_nul();
+    if bytes.len() > length as usize {
+        return -1;
+    }
+    let buffer = slice::from_raw_parts_mut(buffer as *mut u8, bytes.len());
+    buffer.copy_from_slice(bytes);
+    bytes.len() as c_int
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn update_and_remove_last_error() {
+        update_last_error(anyhow!("some error"));
+        assert!(last_error_length() > 0);
+
+        remove_last_error();
+        assert_eq!(last_error_length(), 0);
+    }
+}
@@ -4,7 +4,11 @@ use crate::ffi::error::update_last_err_if_required;
 use anyhow::{bail, Context, Result};
 use clap::crate_name;
 use libc::c_char;
-use std::{env, ffi::CStr};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    env,
+    ffi::{CStr, CString},
+};
 
 #[no_mangle]
 /// Init the log level by the provided level string.
@@ -24,14 +28,109 @@ fn log_init_res(level: *const c_char) -> Result<()> {
     env_logger::try_init().context("init log level")
 }
 
+/// A host provided log callback, receiving a numeric level (`0` = error,
+/// `1` = warn, `2` = info, `3` = debug, `4` = trace) and a NUL-terminated,
+/// formatted log message.
+pub type LogCallback = extern "C" fn(level: u32, message: *const c_char);
+
+/// Forwards every log record to a host provided [`LogCallback`] instead of
+/// printing it, so the embedding application can route it into its own
+/// logging system.
+struct CallbackLogger {
+    callback: LogCallback,
+}
+
+impl Log for CallbackLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        forward_to_callback(self.callback, record);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Map `record` to the `(level, message)` pair of a [`LogCallback`] and
+/// invoke it. Split out of [`CallbackLogger::log`] so it can be exercised
+/// directly without going through the process-global [`log::max_level`]
+/// filter `CallbackLogger::enabled` relies on.
+fn forward_to_callback(callback: LogCallback, record: &Record) {
+    let level = match record.level() {
+        Level::Error => 0,
+        Level::Warn => 1,
+        Level::Info => 2,
+        Level::Debug => 3,
+        Level::Trace => 4,
+    };
+    if let Ok(message) = CString::new(format!("{}", record.args())) {
+        callback(level, message.as_ptr());
+    }
+}
+
+#[no_mangle]
+/// Register a host provided log `callback`. Every subsequent log record is
+/// forwarded to it instead of being printed, letting the embedding
+/// application route logs into its own logging system. Populates the last
+/// error on failure, e.g. if a logger has already been installed via this
+/// function or `log_init`.
+pub extern "C" fn log_set_callback(callback: LogCallback) {
+    update_last_err_if_required(log_set_callback_res(callback))
+}
+
+fn log_set_callback_res(callback: LogCallback) -> Result<()> {
+    log::set_boxed_logger(Box::new(CallbackLogger { callback })).context("install log callback")?;
+    log::set_max_level(LevelFilter::Trace);
+    Ok(())
+}
+
+#[no_mangle]
+/// Set the log level filter, independently of whether `log_init` or
+/// `log_set_callback` installed the logger. Accepts the same level
+/// strings as `log_init` (e.g. `"debug"`). Populates the last error on
+/// failure. The logger installed by `log_init` additionally applies the
+/// level passed to `log_init`, so with it this filter can only narrow the
+/// output. `log_set_callback` resets the filter to `trace`.
+pub extern "C" fn log_set_level(level: *const c_char) {
+    update_last_err_if_required(log_set_level_res(level))
+}
+
+fn log_set_level_res(level: *const c_char) -> Result<()> {
+    if level.is_null() {
+        bail!("provided log level is NULL")
+    }
+    let level_str = unsafe { CStr::from_ptr(level) }
+        .to_str()
+        .context("convert log level string")?;
+    let filter: LevelFilter = level_str.parse().context("parse log level")?;
+    log::set_max_level(filter);
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::ffi::error::last_error_length;
-    use std::ptr;
+    use std::{
+        ptr,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Mutex,
+        },
+    };
+
+    /// Guards every test that reads or mutates the process-global
+    /// `log::max_level` filter, which `cargo test`'s multi-threaded unit
+    /// test runner would otherwise race on.
+    static LOG_STATE_LOCK: Mutex<()> = Mutex::new(());
 
     #[test]
     fn log_init_success() {
+        let _guard = LOG_STATE_LOCK.lock().unwrap();
         log_init("error\0".as_ptr() as *const c_char);
         assert_eq!(last_error_length(), 0);
     }
@@ -41,4 +140,58 @@ mod tests {
         log_init(ptr::null() as *const c_char);
         assert!(last_error_length() > 0);
     }
+
+    #[test]
+    fn log_set_level_success() {
+        let _guard = LOG_STATE_LOCK.lock().unwrap();
+        log_set_level("debug\0".as_ptr() as *const c_char);
+        assert_eq!(last_error_length(), 0);
+    }
+
+    #[test]
+    fn log_set_level_failure_null() {
+        log_set_level(ptr::null() as *const c_char);
+        assert!(last_error_length() > 0);
+    }
+
+    #[test]
+    fn log_set_level_failure_invalid_level() {
+        log_set_level("not-a-level\0".as_ptr() as *const c_char);
+        assert!(last_error_length() > 0);
+    }
+
+    static LAST_CALLBACK_LEVEL: AtomicU32 = AtomicU32::new(u32::MAX);
+
+    extern "C" fn record_last_level(level: u32, _message: *const c_char) {
+        LAST_CALLBACK_LEVEL.store(level, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn callback_logger_forwards_records() {
+        // Exercises `forward_to_callback` directly rather than going
+        // through `CallbackLogger::log`/`enabled`, which gate on the
+        // process-global `log::max_level()` — shared, unsynchronized
+        // state that `log_init_success`/`log_set_level_success` also
+        // mutate, and `cargo test` runs unit tests on multiple threads by
+        // default.
+        forward_to_callback(
+            record_last_level,
+            &Record::builder()
+                .level(Level::Warn)
+                .args(format_args!("some message"))
+                .build(),
+        );
+        assert_eq!(LAST_CALLBACK_LEVEL.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn callback_logger_enabled_respects_global_filter() {
+        let _guard = LOG_STATE_LOCK.lock().unwrap();
+        log::set_max_level(LevelFilter::Warn);
+        let logger = CallbackLogger {
+            callback: record_last_level,
+        };
+        assert!(logger.enabled(&Metadata::builder().level(Level::Warn).build()));
+        assert!(!logger.enabled(&Metadata::builder().level(Level::Debug).build()));
+    }
 }
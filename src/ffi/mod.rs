@@ -0,0 +1,5 @@
+//! C FFI bindings exposed by this crate.
+
+pub mod error;
+pub mod log;
+pub mod network;
@@ -0,0 +1,3 @@
+//! CNI related FFI interfaces.
+
+pub mod port;
@@ -2,14 +2,21 @@
 
 use crate::{
     ffi::error::{remove_last_error, update_last_err_if_required, update_last_error},
-    network::cni::port::{PortManager, PortMapping as NativePortMappings, PortMappingBuilder},
+    network::cni::port::{
+        PortManager, PortMapping as NativePortMappings, PortMappingBuilder, Protocol,
+    },
 };
 use anyhow::{anyhow, bail, format_err, Context, Result};
 use async_trait::async_trait;
 use dyn_clone::{clone_trait_object, DynClone};
 use ipnetwork::IpNetwork;
 use libc::{c_char, c_void};
-use std::{ffi::CStr, net::SocketAddr, ptr, slice};
+use std::{
+    ffi::{CStr, CString},
+    net::{IpAddr, SocketAddr},
+    ptr, slice,
+    sync::OnceLock,
+};
 use tokio::runtime::Runtime;
 
 #[async_trait]
@@ -26,10 +33,32 @@ trait Manager: DynClone + Send + Sync {
     async fn remove_impl(&mut self, _id: &str) -> Result<()> {
         Ok(())
     }
+
+    async fn list_impl(&mut self, _id: &str) -> Result<Vec<NativePortMappings>> {
+        Ok(Vec::new())
+    }
 }
 
 clone_trait_object!(Manager);
 
+/// The state stored behind the opaque `*mut c_void` handed out by
+/// `port_manager_new`: the manager itself plus a long-lived runtime used to
+/// drive its `async` methods, so that `add`/`remove` calls no longer pay
+/// for spinning up a fresh runtime each time.
+struct PortManagerHandle {
+    manager: Box<dyn Manager>,
+    runtime: Runtime,
+}
+
+impl PortManagerHandle {
+    fn new(manager: Box<dyn Manager>) -> Result<Self> {
+        Ok(Self {
+            manager,
+            runtime: Runtime::new().context("create port manager runtime")?,
+        })
+    }
+}
+
 #[async_trait]
 impl Manager for PortManager {
     async fn add_impl(
@@ -44,6 +73,10 @@ impl Manager for PortManager {
     async fn remove_impl(&mut self, id: &str) -> Result<()> {
         self.remove(id).await
     }
+
+    async fn list_impl(&mut self, id: &str) -> Result<Vec<NativePortMappings>> {
+        self.list(id).await
+    }
 }
 
 #[derive(Debug)]
@@ -77,8 +110,15 @@ pub struct PortMapping {
 #[no_mangle]
 /// Create a new port manager instance. In case of any error, it will return a
 /// `NULL` pointer and set the globally available last error.
-pub extern "C" fn port_manager_new(storage_path: *const c_char) -> *mut c_void {
-    match port_manager_new_res(storage_path) {
+///
+/// `gateway_addr` is optional (pass `NULL` to disable it): when set to the
+/// IP address of the host's upstream NAT gateway, every installed mapping
+/// additionally requests forwarding on that gateway via PCP/NAT-PMP.
+pub extern "C" fn port_manager_new(
+    storage_path: *const c_char,
+    gateway_addr: *const c_char,
+) -> *mut c_void {
+    match port_manager_new_res(storage_path, gateway_addr) {
         Err(e) => {
             update_last_error(e);
             ptr::null_mut()
@@ -90,19 +130,42 @@ pub extern "C" fn port_manager_new(storage_path: *const c_char) -> *mut c_void {
     }
 }
 
-fn port_manager_new_res(storage_path: *const c_char) -> Result<*mut c_void> {
+fn port_manager_new_res(
+    storage_path: *const c_char,
+    gateway_addr: *const c_char,
+) -> Result<*mut c_void> {
     if storage_path.is_null() {
         bail!("provided storage path is NULL")
     }
-    Ok(Box::into_raw(Box::new(Box::new(
-        Runtime::new()?
-            .block_on(PortManager::new(
-                unsafe { CStr::from_ptr(storage_path) }
-                    .to_str()
-                    .context("convert storage path string")?,
-            ))
-            .context("create port manager")?,
-    ) as Box<dyn Manager>)) as *mut c_void)
+    let storage_path_str = unsafe { CStr::from_ptr(storage_path) }
+        .to_str()
+        .context("convert storage path string")?;
+    let gateway_addr: Option<IpAddr> = if gateway_addr.is_null() {
+        None
+    } else {
+        let gateway_addr_str = unsafe { CStr::from_ptr(gateway_addr) }
+            .to_str()
+            .context("convert gateway address string")?;
+        Some(
+            gateway_addr_str
+                .parse()
+                .with_context(|| format_err!("parse gateway address {}", gateway_addr_str))?,
+        )
+    };
+
+    let runtime = Runtime::new().context("create port manager runtime")?;
+    let manager = runtime
+        .block_on(async {
+            match gateway_addr {
+                Some(gateway_addr) => PortManager::with_gateway(storage_path_str, gateway_addr).await,
+                None => PortManager::new(storage_path_str).await,
+            }
+        })
+        .context("create port manager")?;
+    Ok(Box::into_raw(Box::new(PortManagerHandle {
+        manager: Box::new(manager),
+        runtime,
+    })) as *mut c_void)
 }
 
 #[no_mangle]
@@ -112,11 +175,25 @@ pub extern "C" fn port_manager_destroy(port_manager: *mut c_void) {
     if port_manager.is_null() {
         update_last_error(anyhow!("provided port manager is NULL"));
     } else {
-        unsafe { Box::from_raw(port_manager as *mut Box<dyn Manager>) };
+        unsafe { Box::from_raw(port_manager as *mut PortManagerHandle) };
         remove_last_error();
     }
 }
 
+static SUPPORTED_PROTOCOLS: OnceLock<CString> = OnceLock::new();
+
+#[no_mangle]
+/// Return a comma separated, NUL-terminated list of the port mapping
+/// protocols accepted by `port_manager_add`, e.g. `"tcp,udp,sctp"`. Callers
+/// can use this to validate a protocol before attempting to add a mapping.
+/// The returned pointer is valid for the lifetime of the process and must
+/// not be freed.
+pub extern "C" fn port_manager_supported_protocols() -> *const c_char {
+    SUPPORTED_PROTOCOLS
+        .get_or_init(|| CString::new(Protocol::ACCEPTED.join(",")).unwrap_or_default())
+        .as_ptr()
+}
+
 #[no_mangle]
 /// Add port mappings to the port manager.
 /// Populates the last error on failure.
@@ -194,23 +271,22 @@ fn port_manager_add_res(
         .to_str()
         .context("convert container network string")?;
 
-    Runtime::new()?
-        .block_on(
-            unsafe {
-                (port_manager as *mut Box<dyn Manager>)
-                    .as_mut()
-                    .context("retrieve port manager")?
-            }
-            .add_impl(
-                unsafe { CStr::from_ptr(id) }
-                    .to_str()
-                    .context("convert ID string")?,
-                container_network_str.parse().with_context(|| {
-                    format_err!("parse container network {}", container_network_str)
-                })?,
-                &mappings,
-            ),
-        )
+    let handle = unsafe {
+        (port_manager as *mut PortManagerHandle)
+            .as_mut()
+            .context("retrieve port manager")?
+    };
+    handle
+        .runtime
+        .block_on(handle.manager.add_impl(
+            unsafe { CStr::from_ptr(id) }
+                .to_str()
+                .context("convert ID string")?,
+            container_network_str
+                .parse()
+                .with_context(|| format_err!("parse container network {}", container_network_str))?,
+            &mappings,
+        ))
         .context("add port mappings")
 }
 
@@ -229,22 +305,108 @@ fn port_manager_remove_res(port_manager: *mut c_void, id: *const c_char) -> Resu
         bail!("provided ID is NULL")
     }
 
-    Runtime::new()?
-        .block_on(
-            unsafe {
-                (port_manager as *mut Box<dyn Manager>)
-                    .as_mut()
-                    .context("retrieve port manager")?
-            }
-            .remove_impl(
-                unsafe { CStr::from_ptr(id) }
-                    .to_str()
-                    .context("convert ID string")?,
-            ),
-        )
+    let handle = unsafe {
+        (port_manager as *mut PortManagerHandle)
+            .as_mut()
+            .context("retrieve port manager")?
+    };
+    handle
+        .runtime
+        .block_on(handle.manager.remove_impl(
+            unsafe { CStr::from_ptr(id) }
+                .to_str()
+                .context("convert ID string")?,
+        ))
         .context("remove port mappings")
 }
 
+#[no_mangle]
+/// List the port mappings currently active for `id`. Returns a `NULL`
+/// pointer and populates the last error on failure. On success, the
+/// returned pointer must be released via `port_manager_free_mappings`.
+pub extern "C" fn port_manager_list(
+    port_manager: *mut c_void,
+    id: *const c_char,
+) -> *mut PortMappings {
+    match port_manager_list_res(port_manager, id) {
+        Err(e) => {
+            update_last_error(e);
+            ptr::null_mut()
+        }
+        Ok(mappings) => {
+            remove_last_error();
+            mappings
+        }
+    }
+}
+
+fn port_manager_list_res(
+    port_manager: *mut c_void,
+    id: *const c_char,
+) -> Result<*mut PortMappings> {
+    if port_manager.is_null() {
+        bail!("provided port manager is NULL")
+    }
+    if id.is_null() {
+        bail!("provided ID is NULL")
+    }
+
+    let handle = unsafe {
+        (port_manager as *mut PortManagerHandle)
+            .as_mut()
+            .context("retrieve port manager")?
+    };
+    let native_mappings = handle
+        .runtime
+        .block_on(handle.manager.list_impl(
+            unsafe { CStr::from_ptr(id) }
+                .to_str()
+                .context("convert ID string")?,
+        ))
+        .context("list port mappings")?;
+
+    let mut array = Vec::with_capacity(native_mappings.len());
+    for mapping in &native_mappings {
+        array.push(PortMapping {
+            host_ip: CString::new(mapping.host().ip().to_string())
+                .context("convert host IP to C string")?
+                .into_raw(),
+            host_port: mapping.host().port(),
+            container_port: mapping.container_port(),
+            protocol: CString::new(mapping.protocol().to_string())
+                .context("convert protocol to C string")?
+                .into_raw(),
+        });
+    }
+
+    let length = array.len();
+    let array_ptr = Box::into_raw(array.into_boxed_slice()) as *const PortMapping;
+
+    Ok(Box::into_raw(Box::new(PortMappings {
+        array: array_ptr,
+        length,
+    })))
+}
+
+#[no_mangle]
+/// Free the mappings previously returned by `port_manager_list`.
+pub extern "C" fn port_manager_free_mappings(mappings: *mut PortMappings) {
+    if mappings.is_null() {
+        return;
+    }
+    unsafe {
+        let mappings = Box::from_raw(mappings);
+        let array = Box::from_raw(slice::from_raw_parts_mut(
+            mappings.array as *mut PortMapping,
+            mappings.length,
+        ));
+        for mapping in array.iter() {
+            drop(CString::from_raw(mapping.host_ip as *mut c_char));
+            drop(CString::from_raw(mapping.protocol as *mut c_char));
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -256,7 +418,18 @@ mod tests {
     fn new_port_manager_success() -> Result<()> {
         let temp_dir = tempdir()?;
         let c_string = CString::new(temp_dir.path().display().to_string())?;
-        let port_manager = port_manager_new(c_string.into_raw());
+        let port_manager = port_manager_new(c_string.into_raw(), ptr::null());
+        assert_eq!(last_error_length(), 0);
+        port_manager_destroy(port_manager);
+        Ok(())
+    }
+
+    #[test]
+    fn new_port_manager_success_with_gateway() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let c_string = CString::new(temp_dir.path().display().to_string())?;
+        let gateway = CString::new("127.0.0.1")?;
+        let port_manager = port_manager_new(c_string.into_raw(), gateway.into_raw());
         assert_eq!(last_error_length(), 0);
         port_manager_destroy(port_manager);
         Ok(())
@@ -264,18 +437,42 @@ mod tests {
 
     #[test]
     fn new_port_manager_failure_wrong_storage_path() {
-        let port_manager = port_manager_new("/some/wrong/path\0".as_ptr() as *const c_char);
+        let port_manager = port_manager_new(
+            "/some/wrong/path\0".as_ptr() as *const c_char,
+            ptr::null(),
+        );
         assert!(port_manager.is_null());
         assert!(last_error_length() > 0);
     }
 
     #[test]
     fn new_port_manager_failure_null() {
-        let port_manager = port_manager_new(ptr::null());
+        let port_manager = port_manager_new(ptr::null(), ptr::null());
         assert!(port_manager.is_null());
         assert!(last_error_length() > 0);
     }
 
+    #[test]
+    fn new_port_manager_failure_invalid_gateway_address() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let c_string = CString::new(temp_dir.path().display().to_string())?;
+        let port_manager =
+            port_manager_new(c_string.into_raw(), "not-an-ip\0".as_ptr() as *const c_char);
+        assert!(port_manager.is_null());
+        assert!(last_error_length() > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn supported_protocols_contains_all_accepted_values() {
+        let supported = unsafe { CStr::from_ptr(port_manager_supported_protocols()) }
+            .to_str()
+            .unwrap();
+        for protocol in Protocol::ACCEPTED {
+            assert!(supported.split(',').any(|p| p == *protocol));
+        }
+    }
+
     #[test]
     fn destroy_port_manager_failure() {
         port_manager_destroy(ptr::null_mut());
@@ -287,7 +484,9 @@ mod tests {
 
     impl PortManagerMock {
         pub fn new() -> *mut c_void {
-            Box::into_raw(Box::new(Box::new(PortManagerMock) as Box<dyn Manager>)) as *mut c_void
+            Box::into_raw(Box::new(
+                PortManagerHandle::new(Box::new(PortManagerMock)).unwrap(),
+            )) as *mut c_void
         }
     }
 
@@ -329,6 +528,68 @@ mod tests {
         assert_eq!(last_error_length(), 0);
     }
 
+    #[test]
+    fn add_port_mappings_success_udp_and_sctp() {
+        let port_manager = PortManagerMock::new();
+
+        let mappings = PortMappings {
+            array: [
+                PortMapping {
+                    host_ip: "127.0.0.1\0".as_ptr() as *const c_char,
+                    host_port: 53,
+                    container_port: 53,
+                    protocol: "udp\0".as_ptr() as *const c_char,
+                },
+                PortMapping {
+                    host_ip: "127.0.0.1\0".as_ptr() as *const c_char,
+                    host_port: 3868,
+                    container_port: 3868,
+                    protocol: "sctp\0".as_ptr() as *const c_char,
+                },
+            ]
+            .as_ptr(),
+            length: 2,
+        };
+
+        port_manager_add(
+            port_manager,
+            "id\0".as_ptr() as *const c_char,
+            "127.0.0.1/8\0".as_ptr() as *const c_char,
+            &mappings as *const PortMappings,
+        );
+        assert_eq!(last_error_length(), 0);
+
+        port_manager_destroy(port_manager);
+        assert_eq!(last_error_length(), 0);
+    }
+
+    #[test]
+    fn add_port_mappings_failure_unknown_protocol() {
+        let port_manager = PortManagerMock::new();
+
+        let mappings = PortMappings {
+            array: [PortMapping {
+                host_ip: "127.0.0.1\0".as_ptr() as *const c_char,
+                host_port: 8080,
+                container_port: 8080,
+                protocol: "quic\0".as_ptr() as *const c_char,
+            }]
+            .as_ptr(),
+            length: 1,
+        };
+
+        port_manager_add(
+            port_manager,
+            "id\0".as_ptr() as *const c_char,
+            "127.0.0.1/8\0".as_ptr() as *const c_char,
+            &mappings as *const PortMappings,
+        );
+        assert!(last_error_length() > 0);
+
+        port_manager_destroy(port_manager);
+        assert_eq!(last_error_length(), 0);
+    }
+
     #[test]
     fn add_port_mappings_failure_port_manager_null() {
         let mappings = PortMappings {
@@ -487,4 +748,42 @@ mod tests {
         port_manager_destroy(port_manager);
         assert_eq!(last_error_length(), 0);
     }
+
+    #[test]
+    fn list_port_mappings_success() {
+        let port_manager = PortManagerMock::new();
+
+        let mappings = port_manager_list(port_manager, "id\0".as_ptr() as *const c_char);
+        assert_eq!(last_error_length(), 0);
+        assert!(!mappings.is_null());
+        assert_eq!(unsafe { (*mappings).length }, 0);
+
+        port_manager_free_mappings(mappings);
+        port_manager_destroy(port_manager);
+        assert_eq!(last_error_length(), 0);
+    }
+
+    #[test]
+    fn list_port_mappings_failure_port_manager_null() {
+        let mappings = port_manager_list(ptr::null_mut(), "id\0".as_ptr() as *const c_char);
+        assert!(mappings.is_null());
+        assert!(last_error_length() > 0);
+    }
+
+    #[test]
+    fn list_port_mappings_failure_id_null() {
+        let port_manager = PortManagerMock::new();
+
+        let mappings = port_manager_list(port_manager, ptr::null() as *const c_char);
+        assert!(mappings.is_null());
+        assert!(last_error_length() > 0);
+
+        port_manager_destroy(port_manager);
+        assert_eq!(last_error_length(), 0);
+    }
+
+    #[test]
+    fn free_mappings_null_is_noop() {
+        port_manager_free_mappings(ptr::null_mut());
+    }
 }
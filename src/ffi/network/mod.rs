@@ -0,0 +1,3 @@
+//! Networking related FFI interfaces.
+
+pub mod cni;
@@ -0,0 +1,5 @@
+//! Rust implementation of a Container Runtime Interface (CRI) server, with
+//! a C FFI exposed for embedding into other runtimes.
+
+pub mod ffi;
+pub mod network;
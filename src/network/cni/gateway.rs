@@ -0,0 +1,332 @@
+//! Client for requesting upstream NAT port forwarding on the host's
+//! gateway via Port Control Protocol (PCP, RFC 6887), falling back to
+//! NAT-PMP (RFC 6886) when the gateway does not speak PCP.
+
+use super::port::Protocol;
+use anyhow::{bail, Context, Result};
+use rand::RngCore;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, time::timeout};
+
+/// The well known port both PCP and NAT-PMP gateways listen on.
+const GATEWAY_PORT: u16 = 5351;
+
+/// How long to wait for a gateway response before giving up on the
+/// attempted protocol.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The outcome of a successful gateway mapping request.
+#[derive(Clone, Copy, Debug)]
+pub struct GatewayMapping {
+    /// The external port assigned by the gateway.
+    pub external_port: u16,
+
+    /// The lifetime, in seconds, the gateway granted the mapping for.
+    pub lifetime: u32,
+}
+
+/// A client for a single upstream NAT gateway, speaking PCP with a
+/// NAT-PMP fallback.
+#[derive(Clone, Debug)]
+pub struct GatewayClient {
+    gateway: SocketAddr,
+}
+
+impl GatewayClient {
+    /// Create a client talking to the gateway at `gateway_addr` on the
+    /// well known PCP/NAT-PMP port.
+    pub fn new(gateway_addr: IpAddr) -> Self {
+        Self {
+            gateway: SocketAddr::new(gateway_addr, GATEWAY_PORT),
+        }
+    }
+
+    /// Request that `internal_port` be forwarded from an external port on
+    /// the gateway for `lifetime` seconds, preferring
+    /// `suggested_external_port` when the gateway is able to honor it.
+    pub async fn map(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        suggested_external_port: u16,
+        lifetime: u32,
+    ) -> Result<GatewayMapping> {
+        match self
+            .pcp_map(protocol, internal_port, suggested_external_port, lifetime)
+            .await
+        {
+            Ok(mapping) => Ok(mapping),
+            Err(_) => {
+                self.nat_pmp_map(protocol, internal_port, suggested_external_port, lifetime)
+                    .await
+            }
+        }
+    }
+
+    /// Delete a previously requested mapping for `internal_port`.
+    pub async fn delete(&self, protocol: Protocol, internal_port: u16) -> Result<()> {
+        match self.pcp_map(protocol, internal_port, 0, 0).await {
+            Ok(_) => Ok(()),
+            Err(_) => self
+                .nat_pmp_map(protocol, internal_port, 0, 0)
+                .await
+                .map(|_| ()),
+        }
+    }
+
+    /// Send a PCP `MAP` request (RFC 6887, section 11 and 19.2).
+    async fn pcp_map(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        suggested_external_port: u16,
+        lifetime: u32,
+    ) -> Result<GatewayMapping> {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .context("bind PCP socket")?;
+        socket
+            .connect(self.gateway)
+            .await
+            .context("connect to PCP gateway")?;
+
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let mut request = Vec::with_capacity(60);
+        request.push(2); // version
+        request.push(1); // opcode: MAP, request (top bit unset)
+        request.extend_from_slice(&[0u8; 2]); // reserved
+        request.extend_from_slice(&lifetime.to_be_bytes());
+        request.extend_from_slice(&client_address_bytes(socket.local_addr()?.ip()));
+        request.extend_from_slice(&nonce);
+        request.push(pcp_protocol_number(protocol));
+        request.extend_from_slice(&[0u8; 3]); // reserved
+        request.extend_from_slice(&internal_port.to_be_bytes());
+        request.extend_from_slice(&suggested_external_port.to_be_bytes());
+        request.extend_from_slice(&client_address_bytes(IpAddr::V4(Ipv4Addr::UNSPECIFIED)));
+
+        socket.send(&request).await.context("send PCP request")?;
+
+        let mut buf = [0u8; 1100];
+        let len = timeout(RESPONSE_TIMEOUT, socket.recv(&mut buf))
+            .await
+            .context("PCP response timed out")?
+            .context("receive PCP response")?;
+        let response = &buf[..len];
+        // The 24 byte common header is all a spec-compliant gateway sends
+        // back for an error response (RFC 6887 section 7.4) — read the
+        // result code before requiring the full 60 byte `MAP` response, so
+        // an error is reported as the result code it actually is instead
+        // of a misleading "too short" message.
+        if response.len() < 24 {
+            bail!("PCP response too short ({} bytes)", response.len());
+        }
+
+        let result_code = response[3];
+        if result_code != 0 {
+            bail!("PCP request failed with result code {}", result_code);
+        }
+
+        if response.len() < 60 {
+            bail!("PCP response too short ({} bytes)", response.len());
+        }
+
+        let lifetime = u32::from_be_bytes([response[4], response[5], response[6], response[7]]);
+        // Bytes 40-41 are the echoed internal port; the assigned external
+        // port follows at bytes 42-43.
+        let external_port = u16::from_be_bytes([response[24 + 18], response[24 + 19]]);
+
+        Ok(GatewayMapping {
+            external_port,
+            lifetime,
+        })
+    }
+
+    /// Send a NAT-PMP mapping request (RFC 6886, section 3.3).
+    async fn nat_pmp_map(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        suggested_external_port: u16,
+        lifetime: u32,
+    ) -> Result<GatewayMapping> {
+        let opcode = match protocol {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+            Protocol::Sctp => bail!("NAT-PMP does not support the SCTP protocol"),
+        };
+
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .context("bind NAT-PMP socket")?;
+        socket
+            .connect(self.gateway)
+            .await
+            .context("connect to NAT-PMP gateway")?;
+
+        let mut request = Vec::with_capacity(12);
+        request.push(0); // version
+        request.push(opcode);
+        request.extend_from_slice(&[0u8; 2]); // reserved
+        request.extend_from_slice(&internal_port.to_be_bytes());
+        request.extend_from_slice(&suggested_external_port.to_be_bytes());
+        request.extend_from_slice(&lifetime.to_be_bytes());
+
+        socket
+            .send(&request)
+            .await
+            .context("send NAT-PMP request")?;
+
+        let mut buf = [0u8; 16];
+        let len = timeout(RESPONSE_TIMEOUT, socket.recv(&mut buf))
+            .await
+            .context("NAT-PMP response timed out")?
+            .context("receive NAT-PMP response")?;
+        if len < 16 {
+            bail!("NAT-PMP response too short ({} bytes)", len);
+        }
+
+        let result_code = u16::from_be_bytes([buf[2], buf[3]]);
+        if result_code != 0 {
+            bail!("NAT-PMP request failed with result code {}", result_code);
+        }
+
+        let lifetime = u32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]);
+        let external_port = u16::from_be_bytes([buf[10], buf[11]]);
+
+        Ok(GatewayMapping {
+            external_port,
+            lifetime,
+        })
+    }
+}
+
+/// Encode `ip` as the 16 byte address field used by PCP, mapping IPv4
+/// addresses into the `::ffff:0:0/96` range as required by RFC 6887.
+fn client_address_bytes(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    }
+}
+
+/// Map a [`Protocol`] to its IANA protocol number, as used by the PCP
+/// `MAP` opcode payload.
+fn pcp_protocol_number(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::Tcp => 6,
+        Protocol::Udp => 17,
+        Protocol::Sctp => 132,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcp_protocol_number_matches_iana() {
+        assert_eq!(pcp_protocol_number(Protocol::Tcp), 6);
+        assert_eq!(pcp_protocol_number(Protocol::Udp), 17);
+        assert_eq!(pcp_protocol_number(Protocol::Sctp), 132);
+    }
+
+    #[test]
+    fn client_address_bytes_maps_ipv4() {
+        let bytes = client_address_bytes(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(&bytes[..12], &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+        assert_eq!(&bytes[12..], &[192, 0, 2, 1]);
+    }
+
+    /// Build a synthetic 60 byte PCP `MAP` success response (RFC 6887
+    /// section 7.4 and 11.1) with distinct internal/external ports, so a
+    /// regression that reads the wrong offset shows up as a mismatch
+    /// rather than accidentally passing.
+    fn pcp_map_response(internal_port: u16, external_port: u16, lifetime: u32) -> Vec<u8> {
+        let mut response = vec![0u8; 60];
+        response[0] = 2; // version
+        response[1] = 0x81; // opcode MAP, response (top bit set)
+        response[3] = 0; // result code: success
+        response[4..8].copy_from_slice(&lifetime.to_be_bytes());
+        // 8..24 epoch + reserved, left zeroed.
+        // 24..36 nonce, 36 protocol, 37..40 reserved, left zeroed.
+        response[40..42].copy_from_slice(&internal_port.to_be_bytes());
+        response[42..44].copy_from_slice(&external_port.to_be_bytes());
+        // 44..60 assigned external address, left zeroed.
+        response
+    }
+
+    /// Round-trips a synthetic PCP `MAP` response through [`GatewayClient::pcp_map`]
+    /// via a loopback UDP "gateway", asserting the parsed external port is
+    /// the one the fake gateway assigned rather than the echoed internal
+    /// port — a regression here previously shipped unnoticed because the
+    /// suggested and assigned ports are equal whenever the gateway honors
+    /// the suggestion verbatim.
+    #[tokio::test]
+    async fn pcp_map_round_trip_parses_assigned_external_port() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let responder = tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            let (len, peer) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(len, 60);
+            let response = pcp_map_response(34000, 41000, 3600);
+            server.send_to(&response, peer).await.unwrap();
+        });
+
+        let client = GatewayClient {
+            gateway: server_addr,
+        };
+        let mapping = client
+            .pcp_map(Protocol::Tcp, 34000, 34000, 3600)
+            .await
+            .unwrap();
+        responder.await.unwrap();
+
+        assert_eq!(mapping.external_port, 41000);
+        assert_ne!(mapping.external_port, 34000);
+        assert_eq!(mapping.lifetime, 3600);
+    }
+
+    /// A spec-compliant gateway truncates an error response to just the 24
+    /// byte common header (RFC 6887 section 7.4) — assert that surfaces as
+    /// the actual result code, not a misleading "too short" error.
+    #[tokio::test]
+    async fn pcp_map_surfaces_result_code_from_truncated_error_response() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let responder = tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            let (len, peer) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(len, 60);
+            let mut response = vec![0u8; 24];
+            response[0] = 2; // version
+            response[1] = 0x81; // opcode MAP, response (top bit set)
+            response[3] = 2; // result code: NOT_AUTHORIZED (RFC 6887 section 7.4)
+            server.send_to(&response, peer).await.unwrap();
+        });
+
+        let client = GatewayClient {
+            gateway: server_addr,
+        };
+        let err = client
+            .pcp_map(Protocol::Tcp, 34000, 34000, 3600)
+            .await
+            .unwrap_err();
+        responder.await.unwrap();
+
+        let message = format!("{:#}", err);
+        assert!(
+            message.contains("result code 2"),
+            "expected error to surface the PCP result code, got: {}",
+            message
+        );
+        assert!(!message.contains("too short"));
+    }
+}
@@ -0,0 +1,4 @@
+//! Container Network Interface (CNI) related functionality.
+
+pub mod gateway;
+pub mod port;
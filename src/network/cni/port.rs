@@ -0,0 +1,702 @@
+//! Native port manager responsible for installing and removing host <->
+//! container port forwarding rules for CNI managed networks.
+
+use crate::network::cni::gateway::GatewayClient;
+use anyhow::{bail, Context, Result};
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{process::Command, sync::Mutex, task::JoinHandle};
+
+/// The IANA registered dynamic/private port range, used as the default
+/// range to pick from when a caller asks for an automatically assigned
+/// host port.
+const DEFAULT_EPHEMERAL_RANGE: RangeInclusive<u16> = 49152..=65535;
+
+/// The lifetime, in seconds, requested for gateway mappings. Renewed at
+/// roughly half this interval for as long as the mapping stays installed.
+const GATEWAY_MAPPING_LIFETIME: u32 = 7200;
+
+/// The transport protocol of a port mapping.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Protocol {
+    /// Transmission Control Protocol.
+    Tcp,
+
+    /// User Datagram Protocol.
+    Udp,
+
+    /// Stream Control Transmission Protocol.
+    Sctp,
+}
+
+impl Protocol {
+    /// All protocol names accepted by [`Protocol::from_str`], exposed so
+    /// callers can validate user input ahead of time.
+    pub const ACCEPTED: &'static [&'static str] = &["tcp", "udp", "sctp"];
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+            Self::Sctp => "sctp",
+        })
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(Self::Tcp),
+            "udp" => Ok(Self::Udp),
+            "sctp" => Ok(Self::Sctp),
+            other => bail!(
+                "unsupported port mapping protocol {:?}, must be one of {:?}",
+                other,
+                Self::ACCEPTED,
+            ),
+        }
+    }
+}
+
+/// A single host <-> container port mapping.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PortMapping {
+    host: SocketAddr,
+    container_port: u16,
+    protocol: Protocol,
+}
+
+impl PortMapping {
+    /// The host socket address of this mapping.
+    pub fn host(&self) -> SocketAddr {
+        self.host
+    }
+
+    /// The port inside the container this mapping forwards to.
+    pub fn container_port(&self) -> u16 {
+        self.container_port
+    }
+
+    /// The transport protocol of this mapping.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Build the `iptables` rule arguments used to install or remove the
+    /// DNAT forwarding rule for this mapping towards `container_ip`, e.g.
+    /// `-p udp --dport 53 -j DNAT --to-destination 10.0.0.2:53`.
+    fn rule_args(&self, action: &str, container_ip: IpAddr) -> Vec<String> {
+        vec![
+            "-t".to_string(),
+            "nat".to_string(),
+            action.to_string(),
+            "PREROUTING".to_string(),
+            "-p".to_string(),
+            self.protocol.to_string(),
+            "--dport".to_string(),
+            self.host.port().to_string(),
+            "-j".to_string(),
+            "DNAT".to_string(),
+            "--to-destination".to_string(),
+            format!("{}:{}", container_ip, self.container_port),
+        ]
+    }
+}
+
+/// Builder for a [`PortMapping`].
+#[derive(Clone, Debug, Default)]
+pub struct PortMappingBuilder {
+    host: Option<SocketAddr>,
+    container_port: Option<u16>,
+    protocol: Option<String>,
+}
+
+impl PortMappingBuilder {
+    /// Set the host socket address of the mapping.
+    pub fn host(mut self, host: SocketAddr) -> Self {
+        self.host = Some(host);
+        self
+    }
+
+    /// Set the port inside the container the mapping forwards to.
+    pub fn container_port(mut self, container_port: u16) -> Self {
+        self.container_port = Some(container_port);
+        self
+    }
+
+    /// Set the protocol of the mapping. The value is only parsed once
+    /// `build` is called, so an invalid protocol surfaces as a single clear
+    /// error instead of silently falling back to a default.
+    pub fn protocol(mut self, protocol: impl Into<String>) -> Self {
+        self.protocol = Some(protocol.into());
+        self
+    }
+
+    /// Build the final [`PortMapping`], failing if a mandatory field is
+    /// missing or the protocol is not one of [`Protocol::ACCEPTED`].
+    pub fn build(self) -> Result<PortMapping> {
+        Ok(PortMapping {
+            host: self.host.context("host socket address not set")?,
+            container_port: self.container_port.context("container port not set")?,
+            protocol: self
+                .protocol
+                .context("protocol not set")?
+                .parse()
+                .context("parse port mapping protocol")?,
+        })
+    }
+}
+
+/// A mapping together with the container address it was installed for,
+/// which is what actually needs to be persisted to reconstruct or tear
+/// down the forwarding rule later.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstalledMapping {
+    mapping: PortMapping,
+    container_ip: IpAddr,
+
+    /// The external port assigned by the upstream NAT gateway, if gateway
+    /// forwarding is enabled for this port manager.
+    gateway_external_port: Option<u16>,
+}
+
+/// On disk representation of the port manager state, keyed by container
+/// id.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Storage {
+    mappings: HashMap<String, Vec<InstalledMapping>>,
+
+    /// Host ports currently claimed, keyed by host IP, so that two
+    /// concurrent `add` calls never hand out the same dynamically
+    /// allocated port.
+    claimed_ports: HashMap<IpAddr, HashSet<u16>>,
+}
+
+impl Storage {
+    fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path).context("read port manager storage")?;
+        if content.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        serde_json::from_str(&content).context("parse port manager storage")
+    }
+
+    fn persist(&self, path: &Path) -> Result<()> {
+        let content =
+            serde_json::to_string_pretty(self).context("serialize port manager storage")?;
+        fs::write(path, content).context("write port manager storage")
+    }
+}
+
+/// Manages host <-> container port forwarding rules for CNI networks,
+/// persisting the active mappings so they can be reconciled after a
+/// restart.
+#[derive(Clone)]
+pub struct PortManager {
+    storage_path: PathBuf,
+    storage: Arc<Mutex<Storage>>,
+    ephemeral_range: RangeInclusive<u16>,
+
+    /// Set when upstream NAT gateway forwarding (PCP/NAT-PMP) is enabled.
+    gateway: Option<Arc<GatewayClient>>,
+
+    /// Background tasks that periodically renew gateway mappings, keyed
+    /// by container id. Intentionally not persisted: after a restart the
+    /// mappings are reconciled from scratch via `add`.
+    gateway_renewals: Arc<Mutex<HashMap<String, Vec<JoinHandle<()>>>>>,
+}
+
+impl PortManager {
+    /// Create a new port manager, loading any previously persisted state
+    /// from `storage_path`: either the state file itself, or an existing
+    /// directory in which the state is kept as `port_manager.json`. The
+    /// directory containing the state file must already exist, otherwise
+    /// an error is returned. Dynamically allocated host ports are picked
+    /// from [`DEFAULT_EPHEMERAL_RANGE`]; use
+    /// [`PortManager::with_ephemeral_range`] to customize that. Upstream
+    /// NAT gateway forwarding is disabled; use
+    /// [`PortManager::with_gateway`] to opt in.
+    pub async fn new(storage_path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_options(storage_path, DEFAULT_EPHEMERAL_RANGE, None).await
+    }
+
+    /// Like [`PortManager::new`], but picks dynamically allocated host
+    /// ports from the given `ephemeral_range` instead of the default one.
+    pub async fn with_ephemeral_range(
+        storage_path: impl AsRef<Path>,
+        ephemeral_range: RangeInclusive<u16>,
+    ) -> Result<Self> {
+        Self::with_options(storage_path, ephemeral_range, None).await
+    }
+
+    /// Like [`PortManager::new`], but additionally requests upstream NAT
+    /// gateway forwarding (PCP, falling back to NAT-PMP) for every
+    /// installed mapping, targeting the gateway at `gateway_addr`. Useful
+    /// when the host itself sits behind a router, e.g. edge or home-lab
+    /// deployments.
+    pub async fn with_gateway(
+        storage_path: impl AsRef<Path>,
+        gateway_addr: IpAddr,
+    ) -> Result<Self> {
+        Self::with_options(storage_path, DEFAULT_EPHEMERAL_RANGE, Some(gateway_addr)).await
+    }
+
+    async fn with_options(
+        storage_path: impl AsRef<Path>,
+        ephemeral_range: RangeInclusive<u16>,
+        gateway_addr: Option<IpAddr>,
+    ) -> Result<Self> {
+        let mut storage_path = storage_path.as_ref().to_path_buf();
+        if storage_path.is_dir() {
+            storage_path.push("port_manager.json");
+        }
+        if let Some(parent) = storage_path.parent() {
+            if !parent.as_os_str().is_empty() && !parent.is_dir() {
+                bail!(
+                    "port manager storage directory {} does not exist",
+                    parent.display()
+                );
+            }
+        }
+        let storage = Storage::load(&storage_path)?;
+        Ok(Self {
+            storage_path,
+            storage: Arc::new(Mutex::new(storage)),
+            ephemeral_range,
+            gateway: gateway_addr.map(GatewayClient::new).map(Arc::new),
+            gateway_renewals: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Add the provided `port_mappings` for `id`, installing a forwarding
+    /// rule per mapping towards an address inside `container_network`. A
+    /// mapping whose `host_port` is `0` is assigned a free port from the
+    /// configured ephemeral range; call [`PortManager::list`] afterwards to
+    /// discover which port was actually chosen.
+    ///
+    /// The storage lock is only held for the bookkeeping steps (port
+    /// allocation and, on success, recording the installed mappings); the
+    /// potentially slow `iptables`/gateway calls run outside it so a single
+    /// `add` cannot stall every other `add`/`remove`/`list` call on this
+    /// manager. If a mapping fails partway through, every mapping already
+    /// installed earlier in this call is torn down again and every port
+    /// claimed for this call is released before the error is returned, so
+    /// no state leaks into `storage` for a call that did not succeed as a
+    /// whole.
+    pub async fn add(
+        &mut self,
+        id: &str,
+        container_network: IpNetwork,
+        port_mappings: &[PortMapping],
+    ) -> Result<()> {
+        let container_ip = container_network.ip();
+
+        let mut prepared: Vec<PortMapping> = Vec::with_capacity(port_mappings.len());
+        {
+            let mut storage = self.storage.lock().await;
+            for mapping in port_mappings {
+                let mut mapping = mapping.clone();
+                if mapping.host.port() == 0 {
+                    let port = match allocate_port(
+                        &mut storage,
+                        mapping.host.ip(),
+                        &self.ephemeral_range,
+                    ) {
+                        Ok(port) => port,
+                        Err(err) => {
+                            for claimed in &prepared {
+                                if let Some(ports) = storage.claimed_ports.get_mut(&claimed.host.ip())
+                                {
+                                    ports.remove(&claimed.host.port());
+                                }
+                            }
+                            return Err(err.context("allocate dynamic host port"));
+                        }
+                    };
+                    mapping.host.set_port(port);
+                } else {
+                    storage
+                        .claimed_ports
+                        .entry(mapping.host.ip())
+                        .or_default()
+                        .insert(mapping.host.port());
+                }
+                prepared.push(mapping);
+            }
+        }
+
+        let mut installed = Vec::with_capacity(prepared.len());
+        for (i, mapping) in prepared.iter().enumerate() {
+            match self
+                .install_mapping(id, container_ip, mapping.clone())
+                .await
+            {
+                Ok(entry) => installed.push(entry),
+                Err(err) => {
+                    self.rollback_add(id, &installed, &prepared[i..]).await;
+                    return Err(err);
+                }
+            }
+        }
+
+        let mut storage = self.storage.lock().await;
+        storage
+            .mappings
+            .entry(id.to_string())
+            .or_default()
+            .extend(installed);
+        storage.persist(&self.storage_path)
+    }
+
+    /// Install the forwarding rule, and optionally the gateway mapping, for
+    /// a single already-port-assigned `mapping`. On gateway failure the
+    /// just-installed forwarding rule is rolled back before returning the
+    /// error, so a failed mapping never leaves a rule behind.
+    async fn install_mapping(
+        &self,
+        id: &str,
+        container_ip: IpAddr,
+        mapping: PortMapping,
+    ) -> Result<InstalledMapping> {
+        install_rule(&mapping.rule_args("-A", container_ip))
+            .await
+            .with_context(|| format!("install forwarding rule for {}", id))?;
+
+        let gateway_external_port = match &self.gateway {
+            Some(gateway) => match self
+                .request_gateway_mapping(Arc::clone(gateway), id, &mapping)
+                .await
+                .with_context(|| format!("request gateway mapping for {}", id))
+            {
+                Ok(port) => Some(port),
+                Err(err) => {
+                    let _ = install_rule(&mapping.rule_args("-D", container_ip)).await;
+                    return Err(err);
+                }
+            },
+            None => None,
+        };
+
+        Ok(InstalledMapping {
+            mapping,
+            container_ip,
+            gateway_external_port,
+        })
+    }
+
+    /// Undo a partially applied `add` call: abort the gateway renewal tasks
+    /// `install_mapping` spawned for the mappings in `installed`, tear down
+    /// every one of those fully installed mappings, then release the claimed host
+    /// port for every mapping in `installed` as well as every mapping in
+    /// `not_installed` (the one that failed, plus any never attempted),
+    /// none of which made it into `storage.mappings`.
+    async fn rollback_add(
+        &self,
+        id: &str,
+        installed: &[InstalledMapping],
+        not_installed: &[PortMapping],
+    ) {
+        // Abort the renewal tasks `install_mapping` spawned for these
+        // entries *before* deleting their gateway mappings below: a
+        // still-running renewal racing a `gateway.delete` could otherwise
+        // re-create the external mapping right after it was torn down,
+        // leaking it on the gateway for the rest of its lease. An `add` is
+        // serialized by `&mut self`, and `install_mapping` appends one
+        // renewal task per installed mapping with a gateway mapping, in
+        // order, to the `id` entry, so draining the last `spawned_renewals`
+        // handles removes exactly those tasks and no others.
+        let spawned_renewals = installed
+            .iter()
+            .filter(|entry| entry.gateway_external_port.is_some())
+            .count();
+        if spawned_renewals > 0 {
+            let mut renewals = self.gateway_renewals.lock().await;
+            if let Some(handles) = renewals.get_mut(id) {
+                let drain_from = handles.len().saturating_sub(spawned_renewals);
+                for handle in handles.drain(drain_from..) {
+                    handle.abort();
+                }
+                if handles.is_empty() {
+                    renewals.remove(id);
+                }
+            }
+        }
+
+        for entry in installed {
+            let _ = install_rule(&entry.mapping.rule_args("-D", entry.container_ip)).await;
+            if entry.gateway_external_port.is_some() {
+                if let Some(gateway) = &self.gateway {
+                    let _ = gateway
+                        .delete(entry.mapping.protocol(), entry.mapping.host.port())
+                        .await;
+                }
+            }
+        }
+
+        let mut storage = self.storage.lock().await;
+        for mapping in installed
+            .iter()
+            .map(|entry| &entry.mapping)
+            .chain(not_installed)
+        {
+            if let Some(claimed) = storage.claimed_ports.get_mut(&mapping.host.ip()) {
+                claimed.remove(&mapping.host.port());
+            }
+        }
+    }
+
+    /// Request a gateway mapping for `mapping` and spawn a background task
+    /// that keeps renewing it at roughly half its granted lifetime,
+    /// returning the external port the gateway assigned.
+    async fn request_gateway_mapping(
+        &self,
+        gateway: Arc<GatewayClient>,
+        id: &str,
+        mapping: &PortMapping,
+    ) -> Result<u16> {
+        let protocol = mapping.protocol();
+        let internal_port = mapping.host.port();
+
+        let initial = gateway
+            .map(
+                protocol,
+                internal_port,
+                internal_port,
+                GATEWAY_MAPPING_LIFETIME,
+            )
+            .await?;
+
+        let mut lifetime = initial.lifetime.max(1);
+        let handle = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(u64::from(lifetime) / 2)).await;
+                match gateway
+                    .map(
+                        protocol,
+                        internal_port,
+                        internal_port,
+                        GATEWAY_MAPPING_LIFETIME,
+                    )
+                    .await
+                {
+                    Ok(renewed) => lifetime = renewed.lifetime.max(1),
+                    Err(err) => {
+                        log::warn!(
+                            "failed to renew gateway mapping for port {}: {:#}",
+                            internal_port,
+                            err
+                        );
+                        break;
+                    }
+                }
+            }
+        });
+
+        self.gateway_renewals
+            .lock()
+            .await
+            .entry(id.to_string())
+            .or_default()
+            .push(handle);
+
+        Ok(initial.external_port)
+    }
+
+    /// Return the port mappings currently installed for `id`, or an empty
+    /// list if `id` has no active mappings.
+    pub async fn list(&self, id: &str) -> Result<Vec<PortMapping>> {
+        let storage = self.storage.lock().await;
+        Ok(storage
+            .mappings
+            .get(id)
+            .map(|installed| installed.iter().map(|entry| entry.mapping.clone()).collect())
+            .unwrap_or_default())
+    }
+
+    /// Remove all port mappings previously added for `id`, releasing any
+    /// host ports that were reserved for them and tearing down any
+    /// gateway mapping installed on their behalf.
+    ///
+    /// As with [`PortManager::add`], the storage lock is only held for the
+    /// bookkeeping (dropping `id` from `storage.mappings`, releasing its
+    /// claimed ports, and persisting); the `iptables`/gateway teardown
+    /// calls run afterwards, outside the lock. The renewal tasks for `id`
+    /// are aborted before those teardown calls run, for the same reason
+    /// `rollback_add` aborts them before `gateway.delete`: a still-running
+    /// renewal racing the delete could otherwise re-create the external
+    /// mapping right after it was torn down.
+    pub async fn remove(&mut self, id: &str) -> Result<()> {
+        let installed = {
+            let mut storage = self.storage.lock().await;
+            let installed = storage.mappings.remove(id);
+            if let Some(installed) = &installed {
+                for entry in installed {
+                    if let Some(claimed) = storage.claimed_ports.get_mut(&entry.mapping.host.ip())
+                    {
+                        claimed.remove(&entry.mapping.host.port());
+                    }
+                }
+            }
+            storage.persist(&self.storage_path)?;
+            installed
+        };
+
+        if let Some(handles) = self.gateway_renewals.lock().await.remove(id) {
+            for handle in handles {
+                handle.abort();
+            }
+        }
+
+        if let Some(installed) = installed {
+            for entry in &installed {
+                // Best effort: the rule may already be gone if the network
+                // namespace was torn down first.
+                let _ = install_rule(&entry.mapping.rule_args("-D", entry.container_ip)).await;
+
+                if entry.gateway_external_port.is_some() {
+                    if let Some(gateway) = &self.gateway {
+                        let _ = gateway
+                            .delete(entry.mapping.protocol(), entry.mapping.host.port())
+                            .await;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Pick a free port for `host_ip` from `range`, reserving it in `storage`
+/// so that a concurrent allocation cannot pick the same one.
+fn allocate_port(
+    storage: &mut Storage,
+    host_ip: IpAddr,
+    range: &RangeInclusive<u16>,
+) -> Result<u16> {
+    let claimed = storage.claimed_ports.entry(host_ip).or_default();
+    for port in range.clone() {
+        if claimed.insert(port) {
+            return Ok(port);
+        }
+    }
+    bail!(
+        "no free port available for host {} in range {}-{}",
+        host_ip,
+        range.start(),
+        range.end()
+    )
+}
+
+/// Execute an `iptables` invocation with the given arguments.
+async fn install_rule(args: &[String]) -> Result<()> {
+    let status = Command::new("iptables")
+        .args(args)
+        .status()
+        .await
+        .context("spawn iptables")?;
+    if !status.success() {
+        bail!("iptables exited with {}", status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_from_str_success() {
+        assert_eq!("tcp".parse::<Protocol>().unwrap(), Protocol::Tcp);
+        assert_eq!("UDP".parse::<Protocol>().unwrap(), Protocol::Udp);
+        assert_eq!("Sctp".parse::<Protocol>().unwrap(), Protocol::Sctp);
+    }
+
+    #[test]
+    fn protocol_from_str_failure_unknown() {
+        assert!("quic".parse::<Protocol>().is_err());
+    }
+
+    #[test]
+    fn port_mapping_builder_success() {
+        let mapping = PortMappingBuilder::default()
+            .host("127.0.0.1:8080".parse().unwrap())
+            .container_port(8080)
+            .protocol("udp")
+            .build()
+            .unwrap();
+        assert_eq!(mapping.protocol(), Protocol::Udp);
+    }
+
+    #[test]
+    fn port_mapping_builder_failure_unknown_protocol() {
+        let result = PortMappingBuilder::default()
+            .host("127.0.0.1:8080".parse().unwrap())
+            .container_port(8080)
+            .protocol("quic")
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn port_mapping_builder_failure_missing_fields() {
+        assert!(PortMappingBuilder::default().build().is_err());
+    }
+
+    #[test]
+    fn allocate_port_picks_free_port_in_range() {
+        let mut storage = Storage::default();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        let port = allocate_port(&mut storage, ip, &(50000..=50001)).unwrap();
+        assert!((50000..=50001).contains(&port));
+        assert!(storage.claimed_ports[&ip].contains(&port));
+    }
+
+    #[test]
+    fn allocate_port_failure_range_exhausted() {
+        let mut storage = Storage::default();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        allocate_port(&mut storage, ip, &(50000..=50000)).unwrap();
+        assert!(allocate_port(&mut storage, ip, &(50000..=50000)).is_err());
+    }
+
+    #[tokio::test]
+    async fn list_without_mappings_is_empty() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let manager = PortManager::new(temp_dir.path().join("storage.json"))
+            .await
+            .unwrap();
+        assert!(manager.list("id").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn with_gateway_success() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let manager = PortManager::with_gateway(
+            temp_dir.path().join("storage.json"),
+            "127.0.0.1".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        assert!(manager.gateway.is_some());
+    }
+}
@@ -0,0 +1,3 @@
+//! Networking primitives used by the CRI server implementation.
+
+pub mod cni;